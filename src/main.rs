@@ -1,25 +1,45 @@
 mod motion_detection_camera {
     use once_cell::sync::Lazy;
 
-    use std::sync::mpsc::{self, Receiver, Sender};
+    use std::collections::VecDeque;
+    use std::sync::atomic::{AtomicBool, Ordering};
+    use std::sync::mpsc::{self, Receiver, Sender, SyncSender, TryRecvError};
+    use std::sync::{Arc, Condvar, Mutex};
     use std::thread;
+    use std::time::Duration as StdDuration;
 
     use chrono::{DateTime, Duration, Local};
-    use opencv::core::{absdiff, convert_scale_abs, no_array, Point, Scalar, Size_, CV_32F};
+    use futures::StreamExt;
+    use opencv::core::{
+        absdiff, convert_scale_abs, no_array, Point, Ptr, Scalar, Size_, CV_32F, CV_8UC1,
+    };
+    use opencv::imgcodecs::imwrite;
     use opencv::imgproc::{
         draw_contours, CHAIN_APPROX_SIMPLE, LINE_8, RETR_EXTERNAL, THRESH_BINARY,
     };
     use opencv::prelude::*;
-    use opencv::types::VectorOfMat;
+    use opencv::types::{VectorOfi32, VectorOfMat};
     use opencv::videoio::{
         VideoCapture, VideoWriter, CAP_ANY, CAP_PROP_FPS, CAP_PROP_FRAME_HEIGHT,
         CAP_PROP_FRAME_WIDTH,
     };
+    use opencv::video::{
+        create_background_subtractor_mog2, BackgroundSubtractorMOG2, BackgroundSubtractorMOG2Trait,
+    };
     use opencv::{highgui, imgproc, Result};
+    use retina::client::{Credentials, Demuxed, Session, SessionOptions, SetupOptions, Transport};
+    use retina::codec::CodecItem;
+    use retina::rtsp_types::Url;
+
+    use cpal::traits::{DeviceTrait, HostTrait, StreamTrait};
 
     pub enum RecorderRequest {
-        Start,
-        Frame(Mat),
+        // クリップの ID（拡張子なしのファイル名）。検出時に保存するスナップショットと
+        // 同じ ID を使えるよう、呼び出し側で払い出して渡す
+        Start(String),
+        // 音声キャプチャスレッドから直接送られてくる fire-and-forget のリクエスト。
+        // 映像フレームと同じく録画中かどうかでゲートし、録画タイムラインに揃える
+        AudioSamples(Vec<i16>),
         Stop,
         Shutdown,
     }
@@ -30,25 +50,108 @@ mod motion_detection_camera {
         Err,
     }
 
+    // 段(キャプチャ → 処理 → 録画)の間で使う容量つきキュー。push は待たない。
+    // 詰まったときは最も古い要素を捨てて新しいフレームを優先する(drop-oldest)
+    struct DropOldestQueue<T> {
+        queue: Mutex<VecDeque<T>>,
+        capacity: usize,
+        not_empty: Condvar,
+    }
+
+    impl<T> DropOldestQueue<T> {
+        fn new(capacity: usize) -> Self {
+            Self {
+                queue: Mutex::new(VecDeque::with_capacity(capacity)),
+                capacity,
+                not_empty: Condvar::new(),
+            }
+        }
+
+        fn push(&self, item: T) {
+            let mut queue = self.queue.lock().unwrap();
+            if queue.len() == self.capacity {
+                queue.pop_front();
+            }
+            queue.push_back(item);
+            self.not_empty.notify_one();
+        }
+
+        // `timeout` の間だけ待って、要素が来なければ `None` を返す。
+        // これにより呼び出し側は制御メッセージの受信と交互にポーリングできる
+        fn pop_timeout(&self, timeout: StdDuration) -> Option<T> {
+            let queue = self.queue.lock().unwrap();
+            let (mut queue, _) = self
+                .not_empty
+                .wait_timeout_while(queue, timeout, |q| q.is_empty())
+                .unwrap();
+            queue.pop_front()
+        }
+    }
+
     #[derive(Debug, Clone)]
     pub struct RecorderOption {
         fourcc: i32,
         fps: f64,
         frame_size: Size_<i32>,
         is_color: bool,
+        preroll_secs: f64,
+        encoder_backend: EncoderBackend,
+        audio: Option<AudioOption>,
     }
 
     impl RecorderOption {
-        pub fn new(fourcc: i32, fps: f64, frame_size: Size_<i32>, is_color: bool) -> Self {
+        pub fn new(
+            fourcc: i32,
+            fps: f64,
+            frame_size: Size_<i32>,
+            is_color: bool,
+            preroll_secs: f64,
+        ) -> Self {
             Self {
                 fourcc,
                 fps,
                 frame_size,
                 is_color,
+                preroll_secs,
+                encoder_backend: EncoderBackend::OpenCv,
+                audio: None,
+            }
+        }
+
+        /// 出力エンコーダを切り替える（デフォルトは OpenCV の `VideoWriter`）
+        pub fn with_encoder_backend(mut self, encoder_backend: EncoderBackend) -> Self {
+            self.encoder_backend = encoder_backend;
+            self
+        }
+
+        /// マイク入力を音声トラックとして録画に含める。`EncoderBackend::MuxedMp4Aac` と
+        /// 組み合わせたときだけ実際にトラックとして書き出される
+        pub fn with_audio(mut self, audio: AudioOption) -> Self {
+            self.audio = Some(audio);
+            self
+        }
+
+        fn build_encoder(&self) -> Box<dyn Encoder> {
+            match self.encoder_backend {
+                EncoderBackend::OpenCv => Box::new(OpenCvEncoder::new(
+                    self.fourcc,
+                    self.fps,
+                    self.frame_size,
+                    self.is_color,
+                )),
+                EncoderBackend::Rav1e { bitrate_kbps, speed } => {
+                    Box::new(Rav1eEncoder::new(self.frame_size, self.fps, bitrate_kbps, speed))
+                }
+                EncoderBackend::MuxedMp4Aac { bitrate_kbps } => Box::new(MuxedMp4AacEncoder::new(
+                    self.frame_size,
+                    self.fps,
+                    bitrate_kbps,
+                    self.audio.clone(),
+                )),
             }
         }
 
-        pub fn new_with_cap(fourcc: i32) -> (Self, VideoCapture) {
+        pub fn new_with_cap(fourcc: i32, preroll_secs: f64) -> (Self, VideoCapture) {
             let cap = VideoCapture::new(0, CAP_ANY).unwrap();
 
             let opened = VideoCapture::is_opened(&cap).unwrap();
@@ -69,70 +172,1018 @@ mod motion_detection_camera {
                         height: height as i32,
                     },
                     true,
+                    preroll_secs,
                 ),
                 cap,
             )
         }
+
+        /// ローカルのカメラデバイスだけでなく、RTSP で配信している IP カメラも入力元にできる
+        pub fn new_with_source(
+            fourcc: i32,
+            preroll_secs: f64,
+            source: InputSource,
+        ) -> (Self, FrameSource) {
+            match source {
+                InputSource::LocalDevice(index) => {
+                    let cap = VideoCapture::new(index, CAP_ANY).unwrap();
+
+                    let opened = VideoCapture::is_opened(&cap).unwrap();
+                    if !opened {
+                        panic!("Unable to open camera device {index}!");
+                    }
+
+                    let height = cap.get(CAP_PROP_FRAME_HEIGHT).unwrap();
+                    let width = cap.get(CAP_PROP_FRAME_WIDTH).unwrap();
+                    let fps = cap.get(CAP_PROP_FPS).unwrap();
+
+                    (
+                        Self::new(
+                            fourcc,
+                            fps,
+                            Size_ {
+                                width: width as i32,
+                                height: height as i32,
+                            },
+                            true,
+                            preroll_secs,
+                        ),
+                        FrameSource::Local(cap),
+                    )
+                }
+                InputSource::Rtsp {
+                    url,
+                    transport,
+                    frame_size,
+                    fps,
+                } => {
+                    let (frame_sender, frame_receiver) = mpsc::sync_channel(4);
+                    spawn_rtsp_capture_thread(url, transport, frame_sender);
+
+                    (
+                        Self::new(fourcc, fps, frame_size, true, preroll_secs),
+                        FrameSource::Rtsp(frame_receiver),
+                    )
+                }
+            }
+        }
+
+        // 録画開始までにバッファしておくフレーム数
+        fn preroll_capacity(&self) -> usize {
+            (self.fps * self.preroll_secs).ceil() as usize
+        }
+
+        // 録画開始までにバッファしておく音声サンプル数（チャンネルをまたいだインターリーブ込み）。
+        // 映像の pre-roll と同じ秒数だけ遡れるようにし、トリガー前の音声も録画に残す
+        fn preroll_audio_capacity_samples(&self) -> usize {
+            self.audio
+                .as_ref()
+                .map(|audio| {
+                    (audio.sample_rate as f64 * audio.channels as f64 * self.preroll_secs).ceil()
+                        as usize
+                })
+                .unwrap_or(0)
+        }
+    }
+
+    /// 録画に使うエンコーダの選択肢。`OpenCv` が今まで通りの `mp4v` 書き出しで、
+    /// `Rav1e` はピュア Rust の AV1 エンコーダで IVF コンテナへ書き出す
+    #[derive(Debug, Clone, Copy)]
+    pub enum EncoderBackend {
+        OpenCv,
+        Rav1e { bitrate_kbps: usize, speed: usize },
+        // H.264 + AAC を単一の MP4 にマルチプレクスするバックエンド。
+        // 音声を録画に含めたい場合はこれを選ぶ
+        MuxedMp4Aac { bitrate_kbps: usize },
+    }
+
+    impl EncoderBackend {
+        // このバックエンドが音声トラックを消費するか。これが false のときに
+        // マイクキャプチャスレッドを起こしても、サンプルを渡す先がなく捨てられるだけ
+        fn consumes_audio(&self) -> bool {
+            matches!(self, EncoderBackend::MuxedMp4Aac { .. })
+        }
+    }
+
+    #[derive(Debug, Clone)]
+    pub struct AudioOption {
+        pub device_name: Option<String>,
+        pub sample_rate: u32,
+        pub channels: u16,
+    }
+
+    /// 録画バックエンドが満たすべき最小のインターフェース。
+    /// `recorder_thread` はこのトレイトごしにエンコーダを駆動するので、
+    /// 新しいバックエンドを足すときは `EncoderBackend` にケースを足し、
+    /// `RecorderOption::build_encoder` でそのエンコーダを作るだけでよい
+    pub trait Encoder: Send {
+        fn open(&mut self, path: &str) -> Result<()>;
+        fn write_frame(&mut self, frame: &Mat) -> Result<()>;
+        fn finish(&mut self) -> Result<()>;
+        fn file_extension(&self) -> &'static str;
+
+        // 音声トラックを持たないバックエンドでは何もしなくてよい
+        fn write_audio_samples(&mut self, _samples: &[i16]) -> Result<()> {
+            Ok(())
+        }
+    }
+
+    pub struct OpenCvEncoder {
+        fourcc: i32,
+        fps: f64,
+        frame_size: Size_<i32>,
+        is_color: bool,
+        writer: Option<VideoWriter>,
+    }
+
+    impl OpenCvEncoder {
+        pub fn new(fourcc: i32, fps: f64, frame_size: Size_<i32>, is_color: bool) -> Self {
+            Self {
+                fourcc,
+                fps,
+                frame_size,
+                is_color,
+                writer: None,
+            }
+        }
+    }
+
+    impl Encoder for OpenCvEncoder {
+        fn open(&mut self, path: &str) -> Result<()> {
+            self.writer = Some(VideoWriter::new(
+                path,
+                self.fourcc,
+                self.fps,
+                self.frame_size,
+                self.is_color,
+            )?);
+
+            Ok(())
+        }
+
+        fn write_frame(&mut self, frame: &Mat) -> Result<()> {
+            self.writer
+                .as_mut()
+                .expect("OpenCvEncoder::write_frame called before open")
+                .write(frame)
+        }
+
+        fn finish(&mut self) -> Result<()> {
+            if let Some(writer) = self.writer.as_mut() {
+                writer.release()?;
+            }
+            self.writer = None;
+
+            Ok(())
+        }
+
+        fn file_extension(&self) -> &'static str {
+            "mp4"
+        }
+    }
+
+    // rav1e で AV1 にエンコードし、IVF コンテナへ書き出すバックエンド。
+    // OpenCV の VideoWriter と違ってシステムのコーデックに依存せず、出力も小さい
+    pub struct Rav1eEncoder {
+        frame_size: Size_<i32>,
+        fps: f64,
+        bitrate_kbps: usize,
+        speed: usize,
+        ctx: Option<rav1e::Context<u8>>,
+        output: Option<std::fs::File>,
+    }
+
+    impl Rav1eEncoder {
+        pub fn new(frame_size: Size_<i32>, fps: f64, bitrate_kbps: usize, speed: usize) -> Self {
+            Self {
+                frame_size,
+                fps,
+                bitrate_kbps,
+                speed,
+                ctx: None,
+                output: None,
+            }
+        }
+
+        // BGR の Mat を rav1e が要求する I420 平面の Frame に変換する
+        fn bgr_mat_to_i420_frame(&self, ctx: &rav1e::Context<u8>, frame: &Mat) -> Result<rav1e::Frame<u8>> {
+            let mut yuv = Mat::default();
+            imgproc::cvt_color(frame, &mut yuv, imgproc::COLOR_BGR2YUV_I420, 0)?;
+
+            let width = self.frame_size.width as usize;
+            let height = self.frame_size.height as usize;
+            let data = yuv.data_bytes()?;
+
+            let mut rav1e_frame = ctx.new_frame();
+            let (y_size, uv_size) = (width * height, (width / 2) * (height / 2));
+            rav1e_frame.planes[0].copy_from_raw_u8(&data[0..y_size], width, 1);
+            rav1e_frame.planes[1].copy_from_raw_u8(&data[y_size..y_size + uv_size], width / 2, 1);
+            rav1e_frame.planes[2]
+                .copy_from_raw_u8(&data[y_size + uv_size..y_size + 2 * uv_size], width / 2, 1);
+
+            Ok(rav1e_frame)
+        }
+
+        fn drain_packets(&mut self) -> Result<()> {
+            let ctx = self.ctx.as_mut().expect("Rav1eEncoder not opened");
+            let output = self.output.as_mut().expect("Rav1eEncoder not opened");
+
+            loop {
+                match ctx.receive_packet() {
+                    // rav1e は先行入力したフレームを内部でバッファするため、
+                    // 1回の write_frame で複数パケットがまとめて出てくることがある。
+                    // 書き出した順の通し番号ではなく packet 自身の入力フレーム番号で
+                    // タイムスタンプを打たないと提示順がずれる
+                    Ok(packet) => write_ivf_frame(output, packet.input_frameno, &packet.data)?,
+                    Err(rav1e::EncoderStatus::Encoded) | Err(rav1e::EncoderStatus::NeedMoreData) => {
+                        break
+                    }
+                    Err(rav1e::EncoderStatus::LimitReached) => break,
+                    Err(e) => panic!("rav1e encoding failed: {e:?}"),
+                }
+            }
+
+            Ok(())
+        }
     }
 
+    impl Encoder for Rav1eEncoder {
+        fn open(&mut self, path: &str) -> Result<()> {
+            let mut enc_cfg = rav1e::EncoderConfig::default();
+            enc_cfg.width = self.frame_size.width as usize;
+            enc_cfg.height = self.frame_size.height as usize;
+            enc_cfg.time_base = rav1e::Rational::new(1, self.fps.round() as u64);
+            enc_cfg.bitrate = (self.bitrate_kbps * 1000) as i32;
+            enc_cfg.speed_settings = rav1e::SpeedSettings::from_preset(self.speed);
+
+            let cfg = rav1e::Config::new().with_encoder_config(enc_cfg);
+            self.ctx = Some(cfg.new_context().expect("invalid rav1e encoder config"));
+
+            let mut file = std::fs::File::create(path).expect("unable to create AV1 output file");
+            write_ivf_header(
+                &mut file,
+                self.frame_size.width as u16,
+                self.frame_size.height as u16,
+                self.fps.round() as u32,
+            )?;
+            self.output = Some(file);
+
+            Ok(())
+        }
+
+        fn write_frame(&mut self, frame: &Mat) -> Result<()> {
+            let rav1e_frame = {
+                let ctx = self.ctx.as_ref().expect("Rav1eEncoder not opened");
+                self.bgr_mat_to_i420_frame(ctx, frame)?
+            };
+
+            self.ctx
+                .as_mut()
+                .unwrap()
+                .send_frame(rav1e_frame)
+                .expect("rav1e::send_frame failed");
+
+            self.drain_packets()
+        }
+
+        fn finish(&mut self) -> Result<()> {
+            if let Some(ctx) = self.ctx.as_mut() {
+                ctx.flush();
+            }
+            self.drain_packets().ok();
+
+            self.ctx = None;
+            self.output = None;
+
+            Ok(())
+        }
+
+        fn file_extension(&self) -> &'static str {
+            "ivf"
+        }
+    }
+
+    fn write_ivf_header(
+        out: &mut impl std::io::Write,
+        width: u16,
+        height: u16,
+        fps: u32,
+    ) -> Result<()> {
+        out.write_all(b"DKIF").unwrap();
+        out.write_all(&0u16.to_le_bytes()).unwrap(); // version
+        out.write_all(&32u16.to_le_bytes()).unwrap(); // header length
+        out.write_all(b"AV01").unwrap();
+        out.write_all(&width.to_le_bytes()).unwrap();
+        out.write_all(&height.to_le_bytes()).unwrap();
+        out.write_all(&fps.to_le_bytes()).unwrap(); // timebase denominator (frame rate)
+        out.write_all(&1u32.to_le_bytes()).unwrap(); // timebase numerator
+        out.write_all(&0u32.to_le_bytes()).unwrap(); // frame count (unknown up front)
+        out.write_all(&0u32.to_le_bytes()).unwrap(); // reserved
+
+        Ok(())
+    }
+
+    fn write_ivf_frame(out: &mut impl std::io::Write, frame_no: u64, data: &[u8]) -> Result<()> {
+        out.write_all(&(data.len() as u32).to_le_bytes()).unwrap();
+        out.write_all(&frame_no.to_le_bytes()).unwrap();
+        out.write_all(data).unwrap();
+
+        Ok(())
+    }
+
+    // Annex-B（スタートコード区切り）の NAL 列を、開始コードを除いた NAL 本体ごとに分割する
+    fn split_annexb_nals(data: &[u8]) -> Vec<&[u8]> {
+        let mut nals = Vec::new();
+        let mut start = None;
+        let mut i = 0;
+        while i < data.len() {
+            let code_len = if data[i..].starts_with(&[0, 0, 0, 1]) {
+                Some(4)
+            } else if data[i..].starts_with(&[0, 0, 1]) {
+                Some(3)
+            } else {
+                None
+            };
+
+            if let Some(code_len) = code_len {
+                if let Some(start) = start {
+                    nals.push(&data[start..i]);
+                }
+                i += code_len;
+                start = Some(i);
+            } else {
+                i += 1;
+            }
+        }
+        if let Some(start) = start {
+            nals.push(&data[start..]);
+        }
+
+        nals
+    }
+
+    // openh264 が吐く NAL の種別（末尾 5 ビット）。SPS/PPS は avcC に入れ、
+    // サンプル本体からは取り除く
+    const NAL_TYPE_SPS: u8 = 7;
+    const NAL_TYPE_PPS: u8 = 8;
+
+    // openh264 の Annex-B 出力を、最初の IDR に含まれる SPS/PPS を avcC として、
+    // 残りの NAL を AVCC（4 バイト長プレフィックス）のサンプルとして取り出す
+    fn split_avc_access_unit(annexb: &[u8]) -> (Option<Vec<u8>>, Option<Vec<u8>>, Vec<u8>) {
+        let mut sps = None;
+        let mut pps = None;
+        let mut avcc_sample = Vec::with_capacity(annexb.len());
+
+        for nal in split_annexb_nals(annexb) {
+            let Some(&header) = nal.first() else {
+                continue;
+            };
+            match header & 0x1f {
+                NAL_TYPE_SPS => sps = sps.or_else(|| Some(nal.to_vec())),
+                NAL_TYPE_PPS => pps = pps.or_else(|| Some(nal.to_vec())),
+                _ => {
+                    avcc_sample.extend_from_slice(&(nal.len() as u32).to_be_bytes());
+                    avcc_sample.extend_from_slice(nal);
+                }
+            }
+        }
+
+        (sps, pps, avcc_sample)
+    }
+
+    // H.264 (openh264) でエンコードした映像と AAC (fdk-aac) でエンコードした音声を
+    // ひとつの MP4 にマルチプレクスするバックエンド
+    pub struct MuxedMp4AacEncoder {
+        frame_size: Size_<i32>,
+        fps: f64,
+        bitrate_kbps: usize,
+        audio: Option<AudioOption>,
+        video_encoder: Option<openh264::encoder::Encoder>,
+        audio_encoder: Option<fdk_aac::enc::Encoder>,
+        mp4_writer: Option<mp4::Mp4Writer<std::fs::File>>,
+        video_track_id: u32,
+        audio_track_id: u32,
+        frame_count: u64,
+        audio_samples_written: u64,
+        // AAC は決まったサンプル数ぴったりのフレームしかエンコードできないため、
+        // cpal のコールバックバッファがその境界と揃わない分をここへ持ち越す
+        audio_pcm_buffer: Vec<i16>,
+        // mp4_writer（ひいては音声トラック）ができる前に音声が来た場合に
+        // エンコード済みサンプルを溜めておき、トラック追加後にまとめて書き出す
+        pending_audio_samples: Vec<mp4::Mp4Sample>,
+        // avcC の構築には最初の IDR から取り出す SPS/PPS が要るため、
+        // トラック追加（mp4_writer の生成）は最初のフレームまで遅延させる
+        path: Option<String>,
+    }
+
+    impl MuxedMp4AacEncoder {
+        pub fn new(
+            frame_size: Size_<i32>,
+            fps: f64,
+            bitrate_kbps: usize,
+            audio: Option<AudioOption>,
+        ) -> Self {
+            Self {
+                frame_size,
+                fps,
+                bitrate_kbps,
+                audio,
+                video_encoder: None,
+                audio_encoder: None,
+                mp4_writer: None,
+                video_track_id: 0,
+                audio_track_id: 0,
+                frame_count: 0,
+                audio_samples_written: 0,
+                audio_pcm_buffer: Vec::new(),
+                pending_audio_samples: Vec::new(),
+                path: None,
+            }
+        }
+
+        fn bgr_mat_to_yuv_source(&self, frame: &Mat) -> Result<opencv::core::Mat> {
+            let mut yuv = Mat::default();
+            imgproc::cvt_color(frame, &mut yuv, imgproc::COLOR_BGR2YUV_I420, 0)?;
+            Ok(yuv)
+        }
+
+        // 最初のフレームの SPS/PPS を受け取った時点で mp4 の各トラックを追加する
+        fn open_tracks(&mut self, sps: Vec<u8>, pps: Vec<u8>) {
+            let path = self.path.as_ref().expect("MuxedMp4AacEncoder not opened");
+            let file = std::fs::File::create(path).expect("unable to create mp4 output file");
+            let mp4_config = mp4::Mp4Config {
+                major_brand: "isom".parse().unwrap(),
+                minor_version: 512,
+                compatible_brands: vec!["isom".parse().unwrap(), "mp42".parse().unwrap()],
+                timescale: 1000,
+            };
+
+            let mut writer =
+                mp4::Mp4Writer::write_start(file, &mp4_config).expect("mp4 writer init failed");
+
+            let video_track_id = writer
+                .add_track(&mp4::TrackConfig {
+                    track_type: mp4::TrackType::Video,
+                    timescale: (self.fps.round() as u32).max(1) * 1000,
+                    language: "und".into(),
+                    media_conf: mp4::MediaConfig::AvcConfig(mp4::AvcConfig {
+                        width: self.frame_size.width as u16,
+                        height: self.frame_size.height as u16,
+                        seq_param_set: sps,
+                        pic_param_set: pps,
+                    }),
+                })
+                .expect("failed to add video track");
+
+            if let Some(audio) = &self.audio {
+                let audio_track_id = writer
+                    .add_track(&mp4::TrackConfig {
+                        track_type: mp4::TrackType::Audio,
+                        timescale: audio.sample_rate,
+                        language: "und".into(),
+                        media_conf: mp4::MediaConfig::AacConfig(mp4::AacConfig {
+                            bitrate: (self.bitrate_kbps * 1000) as u32,
+                            profile: mp4::AudioObjectType::AacLowComplexity,
+                            freq_index: mp4::SampleFreqIndex::try_from(audio.sample_rate)
+                                .expect("unsupported AAC sample rate"),
+                            chan_conf: mp4::ChannelConfig::try_from(audio.channels)
+                                .expect("unsupported AAC channel count"),
+                        }),
+                    })
+                    .expect("failed to add audio track");
+                self.audio_track_id = audio_track_id;
+            }
+
+            self.video_track_id = video_track_id;
+            self.mp4_writer = Some(writer);
+
+            // 音声トラックがまだなかった間にエンコードしておいたサンプルを書き出す
+            if self.audio.is_some() {
+                for sample in std::mem::take(&mut self.pending_audio_samples) {
+                    self.mp4_writer
+                        .as_mut()
+                        .unwrap()
+                        .write_sample(self.audio_track_id, &sample)
+                        .expect("failed to write buffered audio sample");
+                }
+            }
+        }
+    }
+
+    impl Encoder for MuxedMp4AacEncoder {
+        fn open(&mut self, path: &str) -> Result<()> {
+            self.path = Some(path.to_string());
+
+            self.video_encoder = Some(
+                openh264::encoder::Encoder::with_api_config(
+                    openh264::OpenH264API::from_source(),
+                    openh264::encoder::EncoderConfig::new(
+                        self.frame_size.width as u32,
+                        self.frame_size.height as u32,
+                    )
+                    .bitrate(openh264::encoder::BitRate::from_bps(
+                        (self.bitrate_kbps * 1000) as u32,
+                    )),
+                )
+                .expect("failed to create H.264 encoder"),
+            );
+
+            // 音声トラックの追加は avcC の SPS/PPS が揃う最初の映像フレームまで
+            // 遅延するが、AAC エンコーダ自体はここで作っておく。そうしないと
+            // 録画開始直後から届く AudioSamples がトラック追加まで捨てられてしまう
+            if let Some(audio) = &self.audio {
+                self.audio_encoder = Some(
+                    fdk_aac::enc::Encoder::new(fdk_aac::enc::EncoderParams {
+                        bit_rate: fdk_aac::enc::BitRate::Cbr((self.bitrate_kbps * 1000) as u32),
+                        sample_rate: audio.sample_rate,
+                        transport: fdk_aac::enc::Transport::Raw,
+                        channels: if audio.channels >= 2 {
+                            fdk_aac::enc::ChannelMode::Stereo
+                        } else {
+                            fdk_aac::enc::ChannelMode::Mono
+                        },
+                    })
+                    .expect("failed to create AAC encoder"),
+                );
+            }
+
+            self.frame_count = 0;
+            self.audio_samples_written = 0;
+            self.audio_pcm_buffer.clear();
+            self.pending_audio_samples.clear();
+
+            Ok(())
+        }
+
+        fn write_frame(&mut self, frame: &Mat) -> Result<()> {
+            let yuv = self.bgr_mat_to_yuv_source(frame)?;
+            let yuv_source = opencv_mat_as_yuv_source(&yuv, self.frame_size)?;
+
+            let encoded = self
+                .video_encoder
+                .as_mut()
+                .expect("MuxedMp4AacEncoder not opened")
+                .encode(&yuv_source)
+                .expect("H.264 encoding failed");
+
+            let mut annexb = Vec::new();
+            encoded.write_vec(&mut annexb);
+            let (sps, pps, avcc_sample) = split_avc_access_unit(&annexb);
+
+            if self.mp4_writer.is_none() {
+                let sps = sps.expect("first encoded access unit has no SPS");
+                let pps = pps.expect("first encoded access unit has no PPS");
+                self.open_tracks(sps, pps);
+            }
+
+            let timescale = (self.fps.round() as u64).max(1) * 1000;
+            self.mp4_writer
+                .as_mut()
+                .unwrap()
+                .write_sample(
+                    self.video_track_id,
+                    &mp4::Mp4Sample {
+                        start_time: self.frame_count * timescale / (self.fps.round() as u64).max(1),
+                        duration: (timescale / (self.fps.round() as u64).max(1)) as u32,
+                        rendering_offset: 0,
+                        is_sync: encoded.frame_type() == openh264::encoder::FrameType::IDR,
+                        bytes: avcc_sample.into(),
+                    },
+                )
+                .expect("failed to write video sample");
+
+            self.frame_count += 1;
+
+            Ok(())
+        }
+
+        fn write_audio_samples(&mut self, samples: &[i16]) -> Result<()> {
+            let Some(audio) = self.audio.as_ref() else {
+                return Ok(());
+            };
+            let channels = audio.channels.max(1) as u64;
+
+            // cpal のコールバックバッファは AAC の 1 フレーム分（チャンネルをまたいだ
+            // インターリーブ込みのサンプル数）の境界と揃うとは限らないので、前回の
+            // 余りに連結した上で、エンコーダが消費しきるまでループする。消費されな
+            // かった末尾は次回呼び出しへ持ち越し、黙って捨てない
+            self.audio_pcm_buffer.extend_from_slice(samples);
+
+            let mut consumed = 0;
+            while consumed < self.audio_pcm_buffer.len() {
+                let Some(encoder) = self.audio_encoder.as_mut() else {
+                    break;
+                };
+
+                let mut aac_data = vec![0u8; 4096];
+                let info = encoder
+                    .encode(&self.audio_pcm_buffer[consumed..], &mut aac_data)
+                    .expect("AAC encoding failed");
+
+                if info.input_consumed == 0 {
+                    // まだ 1 フレーム分に満たない。残りは次回へ持ち越す
+                    break;
+                }
+                consumed += info.input_consumed;
+
+                if info.output_size > 0 {
+                    aac_data.truncate(info.output_size);
+
+                    // トラックの timescale は「1 チャンネルあたりのサンプル数/秒」なので、
+                    // duration もチャンネルをまたいだインターリーブ数ではなくそれに合わせる
+                    let duration_per_channel = (info.input_consumed as u64 / channels) as u32;
+                    let sample = mp4::Mp4Sample {
+                        start_time: self.audio_samples_written,
+                        duration: duration_per_channel,
+                        rendering_offset: 0,
+                        is_sync: true,
+                        bytes: aac_data.into(),
+                    };
+
+                    // avcC の SPS/PPS がまだ揃っておらず mp4 の音声トラックが追加されて
+                    // いない場合は、トラック追加後にまとめて書き出せるよう溜めておく
+                    match self.mp4_writer.as_mut() {
+                        Some(writer) => {
+                            writer
+                                .write_sample(self.audio_track_id, &sample)
+                                .expect("failed to write audio sample");
+                        }
+                        None => self.pending_audio_samples.push(sample),
+                    }
+
+                    self.audio_samples_written += duration_per_channel as u64;
+                }
+            }
+
+            self.audio_pcm_buffer.drain(0..consumed);
+
+            Ok(())
+        }
+
+        fn finish(&mut self) -> Result<()> {
+            if let Some(mut writer) = self.mp4_writer.take() {
+                writer.write_end().expect("failed to finalize mp4 file");
+            }
+            self.video_encoder = None;
+            self.audio_encoder = None;
+            self.audio_pcm_buffer.clear();
+            self.pending_audio_samples.clear();
+            self.path = None;
+
+            Ok(())
+        }
+
+        fn file_extension(&self) -> &'static str {
+            "mp4"
+        }
+    }
+
+    // openh264 の encode() に渡す YUV ソースへの薄いラッパー。
+    // Mat が確保している I420 バッファの中身をコピーし、各プレーンとして見せる
+    fn opencv_mat_as_yuv_source(
+        yuv: &Mat,
+        frame_size: Size_<i32>,
+    ) -> Result<impl openh264::formats::YUVSource + '_> {
+        let width = frame_size.width as usize;
+        let height = frame_size.height as usize;
+        let data = yuv.data_bytes()?;
+
+        Ok(openh264::formats::YUVBuffer::with_data_and_strides(
+            data.to_vec(),
+            (width, height),
+            (width, width / 2, width / 2),
+        ))
+    }
+
+    #[derive(Debug, Clone, Copy, PartialEq, Eq)]
+    pub enum RtspTransport {
+        Tcp,
+        Udp,
+    }
+
+    /// 映像の入力元。ローカルに接続されたカメラだけでなく、
+    /// RTSP で配信している既設の IP カメラも指定できる
+    pub enum InputSource {
+        LocalDevice(i32),
+        Rtsp {
+            url: String,
+            transport: RtspTransport,
+            // RTSP は SDP ネゴシエーション前に解像度/FPS が確定しないため、
+            // VideoWriter を開くのに必要なこれらの値は呼び出し側で指定してもらう
+            frame_size: Size_<i32>,
+            fps: f64,
+        },
+    }
+
+    /// `VideoCapture`（ローカルデバイス）と RTSP デコード結果のどちらからでも
+    /// 同じやり方でフレームを取り出せるようにする入力の抽象
+    pub enum FrameSource {
+        Local(VideoCapture),
+        Rtsp(Receiver<Mat>),
+    }
+
+    impl FrameSource {
+        pub fn read(&mut self, frame: &mut Mat) -> Result<bool> {
+            match self {
+                FrameSource::Local(cap) => cap.read(frame),
+                FrameSource::Rtsp(receiver) => match receiver.recv() {
+                    Ok(mat) => {
+                        *frame = mat;
+                        Ok(true)
+                    }
+                    Err(_) => Ok(false),
+                },
+            }
+        }
+    }
+
+    // retina で受信した H.264 の NAL から Mat を作り、`frame_sender` へ流す
+    fn spawn_rtsp_capture_thread(
+        url: String,
+        transport: RtspTransport,
+        frame_sender: SyncSender<Mat>,
+    ) -> thread::JoinHandle<()> {
+        thread::spawn(move || {
+            let rt = tokio::runtime::Runtime::new().unwrap();
+
+            rt.block_on(async move {
+                let url: Url = url.parse().expect("invalid RTSP URL");
+
+                let transport = match transport {
+                    RtspTransport::Tcp => Transport::Tcp(Default::default()),
+                    RtspTransport::Udp => Transport::Udp(Default::default()),
+                };
+
+                let mut session = Session::describe(
+                    url,
+                    SessionOptions::default().creds(None as Option<Credentials>),
+                )
+                .await
+                .unwrap();
+
+                let video_stream_i = session
+                    .streams()
+                    .iter()
+                    .position(|s| s.media() == "video")
+                    .expect("RTSP stream has no video media");
+
+                session
+                    .setup(video_stream_i, SetupOptions::default().transport(transport))
+                    .await
+                    .unwrap();
+
+                let mut session: Demuxed = session
+                    .play(Default::default())
+                    .await
+                    .unwrap()
+                    .demuxed()
+                    .unwrap();
+
+                let mut decoder = openh264::decoder::Decoder::new().unwrap();
+                // openh264 のデコーダは Annex-B のスタートコード区切りを要求するが、
+                // Retina は AVCC（長さ接頭辞）の access unit を渡してくる。
+                // さらに SDP からしか得られない SPS/PPS を、最初の access unit の前に
+                // 一度だけ差し込んでデコーダへ渡す
+                let mut params_sent = false;
+
+                while let Some(item) = session.next().await {
+                    let item = match item {
+                        Ok(item) => item,
+                        Err(_) => break,
+                    };
+
+                    if let CodecItem::VideoFrame(video_frame) = item {
+                        let mut annexb = Vec::new();
+                        if !params_sent {
+                            if let Some(retina::codec::ParametersRef::Video(video_params)) =
+                                session.streams()[video_stream_i].parameters()
+                            {
+                                annexb.extend_from_slice(&avcc_params_to_annexb(
+                                    video_params.extra_data(),
+                                ));
+                            }
+                            params_sent = true;
+                        }
+                        annexb.extend_from_slice(&avcc_to_annexb(video_frame.data()));
+
+                        if let Ok(Some(yuv)) = decoder.decode(&annexb) {
+                            if let Some(mat) = yuv420_to_bgr_mat(&yuv) {
+                                if frame_sender.send(mat).is_err() {
+                                    break;
+                                }
+                            }
+                        }
+                    }
+                }
+            });
+        })
+    }
+
+    // AVCC（4 バイト長プレフィックス）の NAL 列を Annex-B（スタートコード区切り）へ変換する
+    fn avcc_to_annexb(data: &[u8]) -> Vec<u8> {
+        let mut out = Vec::with_capacity(data.len() + 16);
+        let mut i = 0;
+        while i + 4 <= data.len() {
+            let len = u32::from_be_bytes([data[i], data[i + 1], data[i + 2], data[i + 3]]) as usize;
+            i += 4;
+            if i + len > data.len() {
+                break;
+            }
+            out.extend_from_slice(&[0, 0, 0, 1]);
+            out.extend_from_slice(&data[i..i + len]);
+            i += len;
+        }
+
+        out
+    }
+
+    // SDP 経由で渡される avcC (AVCDecoderConfigurationRecord) を解析し、
+    // 中に入っている SPS/PPS を Annex-B のスタートコード付きで取り出す
+    fn avcc_params_to_annexb(extra_data: &[u8]) -> Vec<u8> {
+        let mut out = Vec::new();
+        if extra_data.len() < 6 {
+            return out;
+        }
+
+        let num_sps = (extra_data[5] & 0x1f) as usize;
+        let mut i = 6;
+        for _ in 0..num_sps {
+            if i + 2 > extra_data.len() {
+                return out;
+            }
+            let len = u16::from_be_bytes([extra_data[i], extra_data[i + 1]]) as usize;
+            i += 2;
+            if i + len > extra_data.len() {
+                return out;
+            }
+            out.extend_from_slice(&[0, 0, 0, 1]);
+            out.extend_from_slice(&extra_data[i..i + len]);
+            i += len;
+        }
+
+        if i >= extra_data.len() {
+            return out;
+        }
+        let num_pps = extra_data[i] as usize;
+        i += 1;
+        for _ in 0..num_pps {
+            if i + 2 > extra_data.len() {
+                return out;
+            }
+            let len = u16::from_be_bytes([extra_data[i], extra_data[i + 1]]) as usize;
+            i += 2;
+            if i + len > extra_data.len() {
+                return out;
+            }
+            out.extend_from_slice(&[0, 0, 0, 1]);
+            out.extend_from_slice(&extra_data[i..i + len]);
+            i += len;
+        }
+
+        out
+    }
+
+    // stride 付きのプレーンから、行ごとにパディングを落として詰めてコピーする。
+    // hardware-aligned な解像度だとデコーダのプレーン幅が width を超えることがあるため
+    fn copy_plane_rows(plane: &[u8], stride: i32, width: i32, height: i32, out: &mut Vec<u8>) {
+        let stride = stride as usize;
+        let width = width as usize;
+        for row in 0..height as usize {
+            let start = row * stride;
+            out.extend_from_slice(&plane[start..start + width]);
+        }
+    }
+
+    // openh264 が出す I420 平面を OpenCV の BGR Mat に変換する。
+    // プレーンの stride と実際のデコード解像度はデコーダ自身が知っているものを使う
+    // （呼び出し側が渡す frame_size は SDP ネゴシエーション前の概算でしかない）
+    fn yuv420_to_bgr_mat(yuv: &openh264::formats::YUVSource) -> Option<Mat> {
+        let (width, height) = (yuv.width(), yuv.height());
+        let (chroma_width, chroma_height) = ((width + 1) / 2, (height + 1) / 2);
+        let (y_stride, u_stride, v_stride) = (yuv.y_stride(), yuv.u_stride(), yuv.v_stride());
+
+        let mut i420 =
+            Vec::with_capacity((width * height + 2 * chroma_width * chroma_height) as usize);
+        copy_plane_rows(yuv.y_with_stride(), y_stride, width, height, &mut i420);
+        copy_plane_rows(yuv.u_with_stride(), u_stride, chroma_width, chroma_height, &mut i420);
+        copy_plane_rows(yuv.v_with_stride(), v_stride, chroma_width, chroma_height, &mut i420);
+
+        let i420_mat = Mat::new_rows_cols_with_data(
+            height * 3 / 2,
+            width,
+            CV_8UC1,
+            i420.as_mut_ptr() as *mut std::ffi::c_void,
+            opencv::core::Mat_AUTO_STEP,
+        )
+        .ok()?;
+
+        let mut bgr = Mat::default();
+        imgproc::cvt_color(&i420_mat, &mut bgr, imgproc::COLOR_YUV2BGR_I420, 0).ok()?;
+
+        Some(bgr)
+    }
+
+    // main ループ(`start_moving_detection_camera`)から録画スレッドへ渡すフレームのキュー容量。
+    // 書き出しが詰まったときはここで背圧をかけず、古いフレームから捨てる
+    const RECORDER_FRAME_QUEUE_CAPACITY: usize = 8;
+
     fn recorder_thread(
         rec_option: RecorderOption,
         req_receiver: Receiver<RecorderRequest>,
         res_sender: Sender<RecorderResponse>,
+        frame_queue: Arc<DropOldestQueue<Mat>>,
     ) -> thread::JoinHandle<()> {
         thread::spawn(move || {
             let mut recording = false;
             let mut writer = None;
+            let preroll_capacity = rec_option.preroll_capacity();
+            let mut preroll_buffer: VecDeque<Mat> = VecDeque::with_capacity(preroll_capacity);
+            let preroll_audio_capacity = rec_option.preroll_audio_capacity_samples();
+            let mut preroll_audio_buffer: VecDeque<Vec<i16>> = VecDeque::new();
+            let mut preroll_audio_len: usize = 0;
 
-            while let Ok(req) = req_receiver.recv() {
-                match req {
-                    RecorderRequest::Start => {
-                        writer = Some(Box::new(
-                            VideoWriter::new(
-                                &format!("{}.mp4", uuid::Uuid::new_v4()),
-                                rec_option.fourcc,
-                                rec_option.fps,
-                                rec_option.frame_size,
-                                rec_option.is_color,
-                            )
-                            .unwrap(),
-                        ));
-
-                        recording = true;
-                        res_sender.send(RecorderResponse::Ok).unwrap();
-                    }
-                    RecorderRequest::Frame(mat) => {
-                        if recording {
-                            if let Some(writer) = writer.as_mut() {
-                                writer.write(&mat).unwrap();
+            'outer: loop {
+                // Start/Stop/Shutdown のような制御メッセージは取りこぼせないので、
+                // フレームより先に(ノンブロッキングで)必ずさばく
+                loop {
+                    match req_receiver.try_recv() {
+                        Ok(RecorderRequest::Start(clip_id)) => {
+                            let mut encoder = rec_option.build_encoder();
+                            let path = format!("{}.{}", clip_id, encoder.file_extension());
+                            encoder.open(&path).unwrap();
 
-                                res_sender.send(RecorderResponse::Ok).unwrap();
-                            } else {
-                                res_sender.send(RecorderResponse::Err).unwrap();
+                            // 録画開始前にバッファしていた分を先頭から書き出す（pre-roll）。
+                            // 映像と音声を同じタイミングで書き出すことで、トリガー前の
+                            // 音声だけ欠けて映像とずれる、ということがないようにする
+                            while let Some(buffered) = preroll_buffer.pop_front() {
+                                encoder.write_frame(&buffered).unwrap();
                             }
-                        } else {
-                            res_sender.send(RecorderResponse::Err).unwrap();
+                            while let Some(buffered) = preroll_audio_buffer.pop_front() {
+                                encoder.write_audio_samples(&buffered).unwrap();
+                            }
+                            preroll_audio_len = 0;
+
+                            writer = Some(encoder);
+
+                            recording = true;
+                            res_sender.send(RecorderResponse::Ok).unwrap();
                         }
-                    }
-                    RecorderRequest::Stop => {
-                        if recording {
-                            if let Some(writer) = writer.as_mut() {
-                                writer.release().unwrap();
+                        Ok(RecorderRequest::AudioSamples(samples)) => {
+                            // fire-and-forget: 音声キャプチャスレッドは応答を待たない
+                            if recording {
+                                if let Some(encoder) = writer.as_mut() {
+                                    encoder.write_audio_samples(&samples).unwrap();
+                                }
+                            } else if preroll_audio_capacity > 0 {
+                                preroll_audio_len += samples.len();
+                                preroll_audio_buffer.push_back(samples);
 
-                                res_sender.send(RecorderResponse::Ok).unwrap();
+                                while preroll_audio_len > preroll_audio_capacity {
+                                    if let Some(dropped) = preroll_audio_buffer.pop_front() {
+                                        preroll_audio_len -= dropped.len();
+                                    } else {
+                                        break;
+                                    }
+                                }
+                            }
+                        }
+                        Ok(RecorderRequest::Stop) => {
+                            if recording {
+                                if let Some(encoder) = writer.as_mut() {
+                                    encoder.finish().unwrap();
+
+                                    res_sender.send(RecorderResponse::Ok).unwrap();
+                                } else {
+                                    res_sender.send(RecorderResponse::Err).unwrap();
+                                }
+
+                                writer = None;
+                                recording = false;
                             } else {
                                 res_sender.send(RecorderResponse::Err).unwrap();
                             }
-
-                            writer = None;
-                            recording = false;
-                        } else {
-                            res_sender.send(RecorderResponse::Err).unwrap();
                         }
+                        Ok(RecorderRequest::Shutdown) => {
+                            res_sender.send(RecorderResponse::Ok).unwrap();
+                            break 'outer;
+                        }
+                        Err(TryRecvError::Empty) => break,
+                        Err(TryRecvError::Disconnected) => break 'outer,
                     }
-                    RecorderRequest::Shutdown => {
-                        res_sender.send(RecorderResponse::Ok).unwrap();
-                        break;
+                }
+
+                // フレームは ack を待たれないので、書き出しが遅くてもここで待つだけで
+                // キャプチャ/処理スレッドをブロックすることはない
+                if let Some(mat) = frame_queue.pop_timeout(StdDuration::from_millis(50)) {
+                    if recording {
+                        if let Some(encoder) = writer.as_mut() {
+                            encoder.write_frame(&mat).unwrap();
+                        }
+                    } else if preroll_capacity > 0 {
+                        if preroll_buffer.len() == preroll_capacity {
+                            preroll_buffer.pop_front();
+                        }
+                        preroll_buffer.push_back(mat);
                     }
                 }
             }
@@ -142,16 +1193,19 @@ mod motion_detection_camera {
     pub struct RecorderClient {
         req_sender: Sender<RecorderRequest>,
         res_receiver: Receiver<RecorderResponse>,
+        frame_queue: Arc<DropOldestQueue<Mat>>,
     }
 
     impl RecorderClient {
         pub fn new(
             req_sender: Sender<RecorderRequest>,
             res_receiver: Receiver<RecorderResponse>,
+            frame_queue: Arc<DropOldestQueue<Mat>>,
         ) -> Self {
             Self {
                 req_sender,
                 res_receiver,
+                frame_queue,
             }
         }
 
@@ -160,6 +1214,12 @@ mod motion_detection_camera {
 
             self.res_receiver.recv().unwrap()
         }
+
+        // フレームは ack を待たない fire-and-forget。これにより録画のスループットは
+        // 書き出し側の速さだけで決まり、キャプチャ/処理のラウンドトリップに縛られない
+        pub fn push_frame(&self, frame: Mat) {
+            self.frame_queue.push(frame);
+        }
     }
 
     pub fn start_recorder_thread(
@@ -167,89 +1227,246 @@ mod motion_detection_camera {
     ) -> (RecorderClient, thread::JoinHandle<()>) {
         let (req_sender, req_receiver) = mpsc::channel();
         let (res_sender, res_receiver) = mpsc::channel();
+        let frame_queue = Arc::new(DropOldestQueue::new(RECORDER_FRAME_QUEUE_CAPACITY));
+
+        if rec_option.encoder_backend.consumes_audio() {
+            if let Some(audio) = &rec_option.audio {
+                spawn_audio_capture_thread(audio.clone(), req_sender.clone());
+            }
+        }
 
-        let rec_thread = recorder_thread(rec_option.clone(), req_receiver, res_sender);
+        let rec_thread = recorder_thread(
+            rec_option.clone(),
+            req_receiver,
+            res_sender,
+            Arc::clone(&frame_queue),
+        );
 
-        (RecorderClient::new(req_sender, res_receiver), rec_thread)
+        (
+            RecorderClient::new(req_sender, res_receiver, frame_queue),
+            rec_thread,
+        )
+    }
+
+    // マイクから PCM をキャプチャし、Start/Stop と同じリクエスト用 channel 経由で
+    // 録画スレッドへ流し込む（応答は待たない fire-and-forget）
+    fn spawn_audio_capture_thread(
+        audio: AudioOption,
+        req_sender: Sender<RecorderRequest>,
+    ) -> thread::JoinHandle<()> {
+        thread::spawn(move || {
+            let host = cpal::default_host();
+            let device = match &audio.device_name {
+                Some(name) => host
+                    .input_devices()
+                    .unwrap()
+                    .find(|d| d.name().map(|n| &n == name).unwrap_or(false))
+                    .expect("audio input device not found"),
+                None => host
+                    .default_input_device()
+                    .expect("no default audio input device"),
+            };
+
+            let config = cpal::StreamConfig {
+                channels: audio.channels,
+                sample_rate: cpal::SampleRate(audio.sample_rate),
+                buffer_size: cpal::BufferSize::Default,
+            };
+
+            let stream = device
+                .build_input_stream(
+                    &config,
+                    move |data: &[i16], _| {
+                        let _ = req_sender.send(RecorderRequest::AudioSamples(data.to_vec()));
+                    },
+                    |err| eprintln!("audio capture error: {err}"),
+                    None,
+                )
+                .expect("failed to build audio input stream");
+
+            stream.play().expect("failed to start audio input stream");
+
+            // このスレッドはプロセスの生存期間中ストリームを保持し続ける
+            loop {
+                thread::park();
+            }
+        })
     }
 
     #[allow(dead_code)]
+    #[derive(Clone, Copy)]
     pub enum ColorMode {
         Normal,
         Gray,
         FrameDelta,
     }
 
+    // 背景差分の取り方。RunningAverage は従来の accumulate_weighted + absdiff、
+    // Mog2 はガウス混合モデルで背景を学習し、照明のゆるやかな変化に強い
+    #[allow(dead_code)]
+    #[derive(Clone, Copy)]
+    pub enum DetectionMode {
+        RunningAverage,
+        Mog2,
+    }
+
     pub struct MovingDetectCameraOption {
         pub color_mode: ColorMode,
         pub plot_contours: bool,
+        // 検知した瞬間の静止画（クリップと同じ ID の .jpg）を保存するか
+        pub save_snapshot: bool,
+        // 保存するスナップショットに、検知した輪郭のバウンディングボックスを描くか
+        pub snapshot_draw_boxes: bool,
+        pub detection_mode: DetectionMode,
+        // MOG2 が背景モデルを更新する速さ。負値だと自動選択になる
+        pub mog2_learning_rate: f64,
+        // この面積未満の輪郭はノイズとみなして無視する
+        pub min_contour_area: f64,
+        // 無視した輪郭を除いた面積の合計がこれ以上になったら「動きあり」とする
+        pub area_trigger_threshold: f64,
+        // 面積がしきい値を超えるフレームがこの回数連続するまでは録画を開始しない（デバウンス）
+        pub trigger_debounce_frames: u32,
     }
 
     static DEFAULT_RECORDING_MIN_LEN: Lazy<Duration> =
         Lazy::new(|| Duration::from_std(std::time::Duration::from_secs(3)).unwrap());
 
-    pub fn start_moving_detection_camera(
-        mut cap: VideoCapture,
-        rec_client: &RecorderClient,
-        mdc_option: MovingDetectCameraOption,
-    ) -> Result<()> {
-        init();
+    // キャプチャ(`cap.read`だけ)と検出処理を別スレッドに分離する際の、
+    // 両者をつなぐキューの容量。処理が詰まってもキャプチャ側はブロックしない
+    const CAPTURE_FRAME_QUEUE_CAPACITY: usize = 4;
 
-        let window_name = "VideoCapture";
-        highgui::named_window(window_name, 1)?;
+    // キャプチャ専任のスレッド。フレームを読んでキューに積むだけで、
+    // 検出処理やディスク書き込みのような重い処理はしない
+    fn capture_thread(
+        mut source: FrameSource,
+        frame_queue: Arc<DropOldestQueue<Mat>>,
+        stop: Arc<AtomicBool>,
+    ) -> thread::JoinHandle<()> {
+        thread::spawn(move || {
+            while !stop.load(Ordering::Relaxed) {
+                let mut frame = Mat::default();
+                if source.read(&mut frame).is_err() {
+                    break;
+                }
+
+                if frame.size().map(|s| s.width > 0).unwrap_or(false) {
+                    frame_queue.push(frame);
+                }
+            }
+        })
+    }
 
-        let mut have_avg = false;
-        let mut avg = Mat::default();
+    // 検出処理スレッドがキャプチャ済みフレームから作る、表示/録画/スナップショットに
+    // 必要な情報一式
+    struct DetectedFrame {
+        frame: Mat,
+        contours: VectorOfMat,
+        moving_area: f64,
+    }
 
-        let mut is_recording = false;
-        let mut start_datetime: Option<DateTime<Local>> = None;
+    // 処理スレッドから main ループへ渡す検出結果のキュー容量。
+    // 描画/録画制御が詰まっても検出処理側はブロックしない
+    const PROCESSED_FRAME_QUEUE_CAPACITY: usize = 4;
 
-        loop {
-            let mut frame = Mat::default();
-            cap.read(&mut frame)?;
+    // 検出専任のスレッド。グレースケール変換、背景差分(MOG2/running average)、
+    // 輪郭抽出と面積計算、輪郭の描画までをここで行い、highgui を触らない
+    // (imshow/wait_key は呼び出し元スレッドに残す)
+    fn processing_thread(
+        capture_queue: Arc<DropOldestQueue<Mat>>,
+        processed_queue: Arc<DropOldestQueue<DetectedFrame>>,
+        stop: Arc<AtomicBool>,
+        color_mode: ColorMode,
+        plot_contours: bool,
+        detection_mode: DetectionMode,
+        mog2_learning_rate: f64,
+        min_contour_area: f64,
+    ) -> thread::JoinHandle<Result<()>> {
+        thread::spawn(move || {
+            let mut have_avg = false;
+            let mut avg = Mat::default();
+            let mut mog2: Option<Ptr<BackgroundSubtractorMOG2>> = None;
+
+            while !stop.load(Ordering::Relaxed) {
+                let frame = match capture_queue.pop_timeout(StdDuration::from_millis(200)) {
+                    Some(frame) => frame,
+                    None => continue,
+                };
 
-            if frame.size()?.width > 0 {
                 let mut gray = Mat::default();
                 imgproc::cvt_color(&frame, &mut gray, imgproc::COLOR_BGR2GRAY, 0)?;
 
-                if !have_avg {
-                    gray.convert_to(&mut avg, CV_32F, 1., 0.)?;
+                let mut contours = VectorOfMat::default();
 
-                    have_avg = true;
-                }
+                let (diff_like, mut frame) = match detection_mode {
+                    DetectionMode::RunningAverage => {
+                        if !have_avg {
+                            gray.convert_to(&mut avg, CV_32F, 1., 0.)?;
+
+                            have_avg = true;
+                        }
 
-                imgproc::accumulate_weighted(&gray, &mut avg, 0.6, &no_array())?;
-                let mut scale_abs = Mat::default();
-                convert_scale_abs(&avg, &mut scale_abs, 1., 0.)?;
-                let mut frame_delta = Mat::default();
-                absdiff(&gray, &scale_abs, &mut frame_delta)?;
+                        imgproc::accumulate_weighted(&gray, &mut avg, 0.6, &no_array())?;
+                        let mut scale_abs = Mat::default();
+                        convert_scale_abs(&avg, &mut scale_abs, 1., 0.)?;
+                        let mut frame_delta = Mat::default();
+                        absdiff(&gray, &scale_abs, &mut frame_delta)?;
 
-                // 平均画素との差分...
-                let threshold1 = 40.;
+                        // 平均画素との差分...
+                        let threshold1 = 40.;
 
-                let mut thresh = Mat::default();
-                imgproc::threshold(&frame_delta, &mut thresh, threshold1, 255., THRESH_BINARY)?;
+                        let mut thresh = Mat::default();
+                        imgproc::threshold(
+                            &frame_delta,
+                            &mut thresh,
+                            threshold1,
+                            255.,
+                            THRESH_BINARY,
+                        )?;
 
-                let mut contours = VectorOfMat::default();
+                        imgproc::find_contours(
+                            &thresh,
+                            &mut contours,
+                            RETR_EXTERNAL,
+                            CHAIN_APPROX_SIMPLE,
+                            Point::default(),
+                        )?;
+
+                        (frame_delta, frame)
+                    }
+                    DetectionMode::Mog2 => {
+                        let subtractor = mog2.get_or_insert_with(|| {
+                            create_background_subtractor_mog2(500, 16., true).unwrap()
+                        });
+
+                        let mut fg_mask = Mat::default();
+                        subtractor.apply(&frame, &mut fg_mask, mog2_learning_rate)?;
+
+                        // MOG2 は影を 127 として出すため、しきい値で純粋な前景(255)だけ残す
+                        let mut thresh = Mat::default();
+                        imgproc::threshold(&fg_mask, &mut thresh, 200., 255., THRESH_BINARY)?;
+
+                        imgproc::find_contours(
+                            &thresh,
+                            &mut contours,
+                            RETR_EXTERNAL,
+                            CHAIN_APPROX_SIMPLE,
+                            Point::default(),
+                        )?;
 
-                let (trans_to_bgr_flag, mut frame) = match mdc_option.color_mode {
-                    ColorMode::Normal => (false, frame),          // もとの画像
-                    ColorMode::Gray => (true, gray),              // グレースケール
-                    ColorMode::FrameDelta => (true, frame_delta), // 動きのあった画素
+                        (fg_mask, frame)
+                    }
+                };
+
+                let (trans_to_bgr_flag, mut frame) = match color_mode {
+                    ColorMode::Normal => (false, frame),       // もとの画像
+                    ColorMode::Gray => (true, gray),           // グレースケール
+                    ColorMode::FrameDelta => (true, diff_like), // 動きのあった画素
                 };
                 if trans_to_bgr_flag {
                     imgproc::cvt_color(&frame.clone(), &mut frame, imgproc::COLOR_GRAY2BGR, 0)?;
                 }
 
-                imgproc::find_contours(
-                    &thresh,
-                    &mut contours,
-                    RETR_EXTERNAL,
-                    CHAIN_APPROX_SIMPLE,
-                    Point::default(),
-                )?;
-
-                if mdc_option.plot_contours {
+                if plot_contours {
                     draw_contours(
                         &mut frame,
                         &contours,
@@ -263,68 +1480,164 @@ mod motion_detection_camera {
                     )?;
                 }
 
-                highgui::imshow(window_name, &frame)?;
+                // 小さすぎる輪郭（ノイズ）を除いた、動いている領域の面積の合計
+                let moving_area: f64 = contours
+                    .iter()
+                    .map(|c| imgproc::contour_area(&c, false).unwrap_or(0.))
+                    .filter(|&area| area >= min_contour_area)
+                    .sum();
 
-                // recoding function
-                {
-                    println!("contours: {:?}", contours.len());
-                    println!(
-                        " - is_recording: {is_recording:?}, start_datetime: {start_datetime:?}"
-                    );
-                    let contours_threshold = 50;
-                    if contours.len() >= contours_threshold {
-                        // しきい値以上に、平均画素から異なる画素が検出された場合、録画を開始する
-                        let now = chrono::Local::now();
-                        println!("move detect! at {:?}", now);
-                        println!("contours: {:?}", contours.len());
+                processed_queue.push(DetectedFrame {
+                    frame,
+                    contours,
+                    moving_area,
+                });
+            }
 
-                        if !is_recording {
-                            is_recording = true;
+            Ok(())
+        })
+    }
 
-                            if let RecorderResponse::Err =
-                                rec_client.send_request(RecorderRequest::Start)
-                            {
-                                panic!("staring record failed!");
-                            }
-                        }
-                        start_datetime = Some(now);
-                    } else if is_recording {
-                        // 録画条件を確認し、満たさなければ停止
-                        let now = chrono::Local::now();
-                        if let Some(start_datetime) = start_datetime {
-                            let diff = now - start_datetime;
-                            println!("recording stop.... ? {:?}", diff);
-                            if diff >= *DEFAULT_RECORDING_MIN_LEN {
-                                println!(" - recording STOP!!!!!!!!!");
-
-                                if let RecorderResponse::Err =
-                                    rec_client.send_request(RecorderRequest::Stop)
-                                {
-                                    panic!("stopping record failed!");
-                                }
+    pub fn start_moving_detection_camera(
+        source: FrameSource,
+        rec_client: &RecorderClient,
+        mdc_option: MovingDetectCameraOption,
+    ) -> Result<()> {
+        init();
 
-                                is_recording = false;
-                            }
+        let window_name = "VideoCapture";
+        highgui::named_window(window_name, 1)?;
+
+        let mut is_recording = false;
+        let mut start_datetime: Option<DateTime<Local>> = None;
+        // 直近で「面積が閾値を超えていた」と判定した連続フレーム数（デバウンス用）
+        let mut consecutive_trigger_frames: u32 = 0;
+
+        let capture_queue = Arc::new(DropOldestQueue::new(CAPTURE_FRAME_QUEUE_CAPACITY));
+        let processed_queue = Arc::new(DropOldestQueue::new(PROCESSED_FRAME_QUEUE_CAPACITY));
+        let stop = Arc::new(AtomicBool::new(false));
+        let capture_handle = capture_thread(source, Arc::clone(&capture_queue), Arc::clone(&stop));
+        let processing_handle = processing_thread(
+            Arc::clone(&capture_queue),
+            Arc::clone(&processed_queue),
+            Arc::clone(&stop),
+            mdc_option.color_mode,
+            mdc_option.plot_contours,
+            mdc_option.detection_mode,
+            mdc_option.mog2_learning_rate,
+            mdc_option.min_contour_area,
+        );
+
+        loop {
+            let DetectedFrame {
+                frame,
+                contours,
+                moving_area,
+            } = match processed_queue.pop_timeout(StdDuration::from_millis(200)) {
+                Some(detected) => detected,
+                None => continue,
+            };
+
+            highgui::imshow(window_name, &frame)?;
+
+            // recoding function
+            {
+                println!("contours: {:?}, moving_area: {moving_area}", contours.len());
+                println!(" - is_recording: {is_recording:?}, start_datetime: {start_datetime:?}");
+
+                if moving_area >= mdc_option.area_trigger_threshold {
+                    consecutive_trigger_frames += 1;
+                } else {
+                    consecutive_trigger_frames = 0;
+                }
+
+                if consecutive_trigger_frames >= mdc_option.trigger_debounce_frames {
+                    // しきい値以上の面積が、デバウンスの回数だけ連続して検出された場合、録画を開始する
+                    let now = chrono::Local::now();
+                    println!("move detect! at {:?}", now);
+                    println!("moving_area: {moving_area}");
+
+                    if !is_recording {
+                        is_recording = true;
+
+                        let clip_id = uuid::Uuid::new_v4().to_string();
+
+                        if mdc_option.save_snapshot {
+                            save_snapshot(&clip_id, &frame, &contours, &mdc_option)?;
                         }
-                    }
 
-                    if is_recording {
-                        // フレーム追加
-                        println!("recording......");
                         if let RecorderResponse::Err =
-                            rec_client.send_request(RecorderRequest::Frame(frame.clone()))
+                            rec_client.send_request(RecorderRequest::Start(clip_id))
                         {
-                            panic!("recording failed!");
+                            panic!("staring record failed!");
+                        }
+                    }
+                    start_datetime = Some(now);
+                } else if is_recording {
+                    // 録画条件を確認し、満たさなければ停止
+                    let now = chrono::Local::now();
+                    if let Some(start_datetime) = start_datetime {
+                        let diff = now - start_datetime;
+                        println!("recording stop.... ? {:?}", diff);
+                        if diff >= *DEFAULT_RECORDING_MIN_LEN {
+                            println!(" - recording STOP!!!!!!!!!");
+
+                            if let RecorderResponse::Err =
+                                rec_client.send_request(RecorderRequest::Stop)
+                            {
+                                panic!("stopping record failed!");
+                            }
+
+                            is_recording = false;
                         }
                     }
                 }
 
-                if highgui::wait_key(10)? > 0 {
-                    break;
+                // 録画中かどうかに関わらず毎フレーム送る（非録画中は recorder 側で pre-roll 用にバッファされる）。
+                // ack を待たない fire-and-forget なので、書き出しが遅れてもここはブロックしない
+                if is_recording {
+                    println!("recording......");
                 }
+                rec_client.push_frame(frame.clone());
+            }
+
+            if highgui::wait_key(10)? > 0 {
+                break;
+            }
+        }
+
+        stop.store(true, Ordering::Relaxed);
+        capture_handle.join().unwrap();
+        processing_handle.join().unwrap()?;
+
+        Ok(())
+    }
+
+    // 検知した瞬間のフレームを、クリップと同じ ID の静止画として保存する
+    fn save_snapshot(
+        clip_id: &str,
+        frame: &Mat,
+        contours: &VectorOfMat,
+        mdc_option: &MovingDetectCameraOption,
+    ) -> Result<()> {
+        let mut snapshot = frame.clone();
+
+        if mdc_option.snapshot_draw_boxes {
+            for contour in contours.iter() {
+                let rect = imgproc::bounding_rect(&contour)?;
+                imgproc::rectangle(
+                    &mut snapshot,
+                    rect,
+                    Scalar::new(0., 0., 255., 0.),
+                    3,
+                    LINE_8,
+                    0,
+                )?;
             }
         }
 
+        imwrite(&format!("{clip_id}.jpg"), &snapshot, &VectorOfi32::new())?;
+
         Ok(())
     }
 
@@ -345,23 +1658,32 @@ mod motion_detection_camera {
 
 fn main() {
     use crate::motion_detection_camera::{
-        start_moving_detection_camera, start_recorder_thread, ColorMode, MovingDetectCameraOption,
-        RecorderOption, RecorderRequest, RecorderResponse,
+        start_moving_detection_camera, start_recorder_thread, ColorMode, DetectionMode,
+        FrameSource, MovingDetectCameraOption, RecorderOption, RecorderRequest, RecorderResponse,
     };
     use opencv::videoio::VideoWriter;
 
     let (rec_option, cap) = RecorderOption::new_with_cap(
         VideoWriter::fourcc('m' as i8, 'p' as i8, '4' as i8, 'v' as i8).unwrap(),
+        3.0,
     );
+    let source = FrameSource::Local(cap);
 
     let (rec_client, thread) = start_recorder_thread(&rec_option);
 
     let mdc_option = MovingDetectCameraOption {
         color_mode: ColorMode::Normal,
         plot_contours: true,
+        save_snapshot: true,
+        snapshot_draw_boxes: true,
+        detection_mode: DetectionMode::Mog2,
+        mog2_learning_rate: -1.,
+        min_contour_area: 200.,
+        area_trigger_threshold: 500.,
+        trigger_debounce_frames: 3,
     };
 
-    start_moving_detection_camera(cap, &rec_client, mdc_option).unwrap();
+    start_moving_detection_camera(source, &rec_client, mdc_option).unwrap();
 
     assert!(RecorderResponse::Ok == rec_client.send_request(RecorderRequest::Shutdown));
 